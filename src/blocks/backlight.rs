@@ -1,21 +1,122 @@
+use std::cmp;
+use std::env;
 use std::fs::OpenOptions;
-use std::io::Read;
+use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::os::unix::io::AsRawFd;
 use std::thread;
 use std::time::{Duration, Instant};
+
 use chan::Sender;
-use inotify::{EventMask, Inotify, WatchMask};
+use dbus::{BusType, Connection, Message};
+use libc;
+use libudev;
 
 use block::{Block, ConfigBlock};
 use config::Config;
 use errors::*;
 use widgets::text::TextWidget;
 use widget::I3BarWidget;
-use input::I3BarEvent;
+use input::{I3BarEvent, MouseButton};
 use scheduler::Task;
 
 use uuid::Uuid;
 
+/// A single piece of a parsed format string: either literal text or a named
+/// placeholder to be substituted at render time.
+enum FormatPart {
+    Text(String),
+    Var(String),
+}
+
+/// A small template engine for the block's `format` string.
+///
+/// Supports `{name}` placeholders (e.g. `{brightness}`); everything else is
+/// copied through verbatim. Parsed once and rendered on each update.
+struct FormatTemplate {
+    parts: Vec<FormatPart>,
+}
+
+impl FormatTemplate {
+    /// Parse a format string into its literal and placeholder parts.
+    fn from_string(format: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = format.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    if !text.is_empty() {
+                        parts.push(FormatPart::Text(text.clone()));
+                        text.clear();
+                    }
+                    let mut var = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => var.push(c),
+                            None => {
+                                return Err(BlockError(
+                                    "backlight".to_string(),
+                                    "Unmatched '{' in format string".to_string(),
+                                ))
+                            }
+                        }
+                    }
+                    parts.push(FormatPart::Var(var));
+                }
+                '}' => {
+                    return Err(BlockError(
+                        "backlight".to_string(),
+                        "Unmatched '}' in format string".to_string(),
+                    ))
+                }
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(FormatPart::Text(text));
+        }
+
+        Ok(FormatTemplate { parts: parts })
+    }
+
+    /// Render the template, substituting each placeholder with its value from
+    /// `vars`. Unknown placeholders render empty.
+    fn render(&self, vars: &[(&str, String)]) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match *part {
+                FormatPart::Text(ref text) => rendered.push_str(text),
+                FormatPart::Var(ref name) => {
+                    if let Some(&(_, ref value)) = vars.iter().find(|&&(key, _)| key == name) {
+                        rendered.push_str(value);
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+/// The default minimum-brightness floor derived from a device's maximum.
+fn default_minimum_brightness(max_brightness: u64) -> u64 {
+    cmp::max(1, (max_brightness as f64 * DEFAULT_MINIMUM_FRACTION) as u64)
+}
+
+/// The error reported when the backlight cannot be powered on/off because the
+/// direct `bl_power` write was denied and logind offers no power toggle.
+fn power_unsupported() -> Error {
+    BlockError(
+        "backlight".to_string(),
+        "Toggling backlight power requires write access to bl_power".to_string(),
+    )
+}
+
 /// Read a brightness value from the given path.
 fn read_brightness(device_file: &Path) -> Result<u64> {
     let mut file = try!(
@@ -37,42 +138,76 @@ fn read_brightness(device_file: &Path) -> Result<u64> {
     )
 }
 
+/// Fraction of `max_brightness` used as the default safety floor, so a
+/// device is never driven all the way to zero and left completely dark.
+const DEFAULT_MINIMUM_FRACTION: f64 = 0.0004;
+
 pub struct BacklitDevice {
     pub max_brightness: u64,
     device_path: PathBuf,
+    /// Exponent used to map raw sysfs units to perceived brightness. `1.0` is
+    /// plain linear behaviour.
+    root_scaling: f64,
+    /// Lowest raw value the device will be driven to.
+    minimum_brightness: u64,
 }
 
 impl BacklitDevice {
-    /// Use the default backlit device, i.e. the first one found in the
-    /// `/sys/class/backlight` directory.
+    /// Use the "best" backlit device exposed by the `backlight` udev
+    /// subsystem: a real firmware/display backlight is preferred over a
+    /// keyboard/LED entry, with ties broken by the highest `max_brightness`.
     pub fn default() -> Result<Self> {
-        let devices = try!(
-            Path::new("/sys/class/backlight")
-                           .read_dir() // Iterate over entries in the directory.
-                           .block_error("backlight",
-                                        "Failed to read backlight device directory")
-        );
-
-        let first_device = try!(match devices.take(1).next() {
+        let context = try!(libudev::Context::new().block_error(
+            "backlight",
+            "Failed to create udev context",
+        ));
+
+        let mut enumerator = try!(libudev::Enumerator::new(&context).block_error(
+            "backlight",
+            "Failed to create udev enumerator",
+        ));
+        try!(enumerator.match_subsystem("backlight").block_error(
+            "backlight",
+            "Failed to filter udev enumerator",
+        ));
+        let devices = try!(enumerator.scan_devices().block_error(
+            "backlight",
+            "Failed to scan backlight devices",
+        ));
+
+        // Prefer a real backlight over a `*_kbd`/LED entry, then the device
+        // with the largest range.
+        let best = devices.max_by_key(|device| {
+            let is_kbd = device
+                .sysname()
+                .to_string_lossy()
+                .contains("kbd");
+            let max = device
+                .attribute_value("max_brightness")
+                .and_then(|value| value.to_string_lossy().parse::<u64>().ok())
+                .unwrap_or(0);
+            (!is_kbd, max)
+        });
+
+        match best {
+            Some(device) => BacklitDevice::from_udev_device(device),
             None => Err(BlockError(
                 "backlight".to_string(),
                 "No backlit devices found".to_string(),
             )),
-            Some(device) => {
-                device.map_err(|_| {
-                    BlockError(
-                        "backlight".to_string(),
-                        "Failed to read default device file".to_string(),
-                    )
-                })
-            }
-        });
+        }
+    }
 
-        let max_brightness = try!(read_brightness(&first_device.path().join("max_brightness")));
+    /// Build a `BacklitDevice` from an enumerated udev device.
+    fn from_udev_device(device: libudev::Device) -> Result<Self> {
+        let device_path = device.syspath().to_path_buf();
+        let max_brightness = try!(read_brightness(&device_path.join("max_brightness")));
 
         Ok(BacklitDevice {
             max_brightness: max_brightness,
-            device_path: first_device.path(),
+            device_path: device_path,
+            root_scaling: 1.0,
+            minimum_brightness: default_minimum_brightness(max_brightness),
         })
     }
 
@@ -95,24 +230,232 @@ impl BacklitDevice {
         Ok(BacklitDevice {
             max_brightness: max_brightness,
             device_path: device_path,
+            root_scaling: 1.0,
+            minimum_brightness: default_minimum_brightness(max_brightness),
         })
     }
 
-    /// Query the brightness value for this backlit device.
-    pub fn brightness(&self) -> Result<u64> {
+    /// Override the perceived-brightness exponent and minimum-brightness floor
+    /// from user configuration. A `minimum_brightness` of `0` keeps the
+    /// derived default.
+    fn configure(&mut self, root_scaling: f64, minimum_brightness: u64) {
+        if root_scaling > 0.0 {
+            self.root_scaling = root_scaling;
+        }
+        if minimum_brightness > 0 {
+            self.minimum_brightness = cmp::min(minimum_brightness, self.max_brightness);
+        }
+    }
+
+    /// Query the raw sysfs brightness of this device, in hardware units.
+    pub fn raw_brightness(&self) -> Result<u64> {
         read_brightness(&self.brightness_file())
     }
 
+    /// Query the perceived brightness of this device, as a percentage.
+    ///
+    /// Raw sysfs brightness is linear in hardware units but perception is
+    /// roughly logarithmic, so the raw fraction is raised to `1 / root_scaling`
+    /// to make adjustments feel uniform across the range.
+    pub fn brightness(&self) -> Result<u64> {
+        let raw = try!(read_brightness(&self.brightness_file()));
+        let fraction = (raw as f64 / self.max_brightness as f64).powf(1.0 / self.root_scaling);
+        Ok((fraction * 100.0).round() as u64)
+    }
+
+    /// Set the perceived brightness for this device, as a percentage.
+    ///
+    /// The percentage is mapped back to raw sysfs units with the inverse of
+    /// the perceived-brightness curve and clamped up to `minimum_brightness`
+    /// so the panel is never driven completely dark.
+    ///
+    /// Writing the `brightness` file directly only works for root, so if the
+    /// direct write is denied we fall back to the systemd-logind session
+    /// interface, which applies the change with elevated privilege on behalf
+    /// of the seat owner.
+    pub fn set_brightness(&self, percent: u64) -> Result<()> {
+        let fraction = (cmp::min(percent, 100) as f64 / 100.0).powf(self.root_scaling);
+        let raw = (self.max_brightness as f64 * fraction).round() as u64;
+        let value = cmp::min(self.max_brightness, cmp::max(raw, self.minimum_brightness));
+        match OpenOptions::new().write(true).open(self.brightness_file()) {
+            Ok(mut file) => {
+                match write!(file, "{}", value) {
+                    Ok(_) => return Ok(()),
+                    Err(ref e) if e.kind() == ErrorKind::PermissionDenied => {}
+                    Err(e) => {
+                        return Err(BlockError(
+                            "backlight".to_string(),
+                            format!("Failed to write brightness file: {}", e),
+                        ))
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::PermissionDenied => {}
+            Err(e) => {
+                return Err(BlockError(
+                    "backlight".to_string(),
+                    format!("Failed to open brightness file: {}", e),
+                ))
+            }
+        }
+
+        self.set_brightness_via_logind(value as u32)
+    }
+
+    /// The device name, i.e. the final path component under
+    /// `/sys/class/backlight`.
+    fn device_name(&self) -> String {
+        self.device_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Ask logind to set the brightness on our behalf over the system bus.
+    ///
+    /// Resolves the current session (via `XDG_SESSION_ID` when present, or
+    /// `GetSessionByPID` for our own PID otherwise) and calls
+    /// `org.freedesktop.login1.Session.SetBrightness("backlight", <device>, value)`.
+    fn set_brightness_via_logind(&self, value: u32) -> Result<()> {
+        let con = try!(Connection::get_private(BusType::System).block_error(
+            "backlight",
+            "Failed to establish D-Bus system bus connection",
+        ));
+
+        let session_path = try!(logind_session_path(&con));
+
+        let msg = try!(
+            Message::new_method_call(
+                "org.freedesktop.login1",
+                &session_path,
+                "org.freedesktop.login1.Session",
+                "SetBrightness",
+            ).map_err(|e| BlockError("backlight".to_string(), e))
+        ).append3("backlight", self.device_name(), value);
+
+        try!(con.send_with_reply_and_block(msg, 1000).block_error(
+            "backlight",
+            "logind SetBrightness call failed",
+        ));
+
+        Ok(())
+    }
+
+    /// Whether the backlight is currently powered on.
+    ///
+    /// Reads the `bl_power` attribute, where `0` (`FB_BLANK_UNBLANK`) means the
+    /// panel backlight is on and any other value means it is blanked. Devices
+    /// without the attribute are assumed to be powered.
+    pub fn powered(&self) -> Result<bool> {
+        let power_file = self.power_file();
+        if !power_file.exists() {
+            return Ok(true);
+        }
+        Ok(try!(read_brightness(&power_file)) == 0)
+    }
+
+    /// Turn the panel backlight on or off by writing the `bl_power` attribute.
+    ///
+    /// `0` (`FB_BLANK_UNBLANK`) powers the backlight on; `1` blanks it. Unlike
+    /// `set_brightness` there is no logind fallback: logind only exposes
+    /// `SetBrightness`, which cannot touch `bl_power`, so a denied write is
+    /// reported as unsupported rather than silently dimming the panel to black.
+    pub fn set_power(&self, on: bool) -> Result<()> {
+        let value = if on { 0 } else { 1 };
+        match OpenOptions::new().write(true).open(self.power_file()) {
+            Ok(mut file) => {
+                match write!(file, "{}", value) {
+                    Ok(_) => Ok(()),
+                    Err(ref e) if e.kind() == ErrorKind::PermissionDenied => Err(power_unsupported()),
+                    Err(e) => Err(BlockError(
+                        "backlight".to_string(),
+                        format!("Failed to write bl_power file: {}", e),
+                    )),
+                }
+            }
+            Err(ref e) if e.kind() == ErrorKind::PermissionDenied => Err(power_unsupported()),
+            Err(e) => Err(BlockError(
+                "backlight".to_string(),
+                format!("Failed to open bl_power file: {}", e),
+            )),
+        }
+    }
+
+    /// The `bl_power` attribute file.
+    fn power_file(&self) -> PathBuf {
+        self.device_path.join("bl_power")
+    }
+
     /// The brightness file itself.
     pub fn brightness_file(&self) -> PathBuf {
         self.device_path.join("brightness")
     }
 }
 
+/// Resolve the object path of the caller's logind session.
+///
+/// Prefer `XDG_SESSION_ID` when it is set, but a stale or invalid value makes
+/// `GetSession` fail, so fall back to resolving our own PID via
+/// `GetSessionByPID` rather than treating the env var as authoritative.
+fn logind_session_path(con: &Connection) -> Result<dbus::Path<'static>> {
+    if let Ok(id) = env::var("XDG_SESSION_ID") {
+        if let Ok(path) = logind_get_session(con, &id) {
+            return Ok(path);
+        }
+    }
+
+    logind_get_session_by_pid(con, process::id())
+}
+
+/// Resolve a session object path from its logind session id.
+fn logind_get_session(con: &Connection, id: &str) -> Result<dbus::Path<'static>> {
+    let msg = try!(
+        Message::new_method_call(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            "GetSession",
+        ).map_err(|e| BlockError("backlight".to_string(), e))
+    ).append1(id);
+
+    logind_session_reply(con, msg)
+}
+
+/// Resolve the session object path owning the given PID.
+fn logind_get_session_by_pid(con: &Connection, pid: u32) -> Result<dbus::Path<'static>> {
+    let msg = try!(
+        Message::new_method_call(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            "GetSessionByPID",
+        ).map_err(|e| BlockError("backlight".to_string(), e))
+    ).append1(pid);
+
+    logind_session_reply(con, msg)
+}
+
+/// Send a session-resolution call and extract the returned object path.
+fn logind_session_reply(con: &Connection, msg: Message) -> Result<dbus::Path<'static>> {
+    let reply = try!(con.send_with_reply_and_block(msg, 1000).block_error(
+        "backlight",
+        "Failed to resolve logind session",
+    ));
+
+    reply
+        .get1::<dbus::Path>()
+        .map(|p| p.into_static())
+        .block_error("backlight", "logind returned no session path")
+}
+
 pub struct Backlight {
     id: String,
     output: TextWidget,
     device: BacklitDevice,
+    step_width: u64,
+    format: FormatTemplate,
+    toggle_button: MouseButton,
+    update_request: Sender<Task>,
 }
 
 #[derive(Deserialize, Debug, Default, Clone)]
@@ -121,51 +464,114 @@ pub struct BacklightConfig {
     /// The backlight device in `/sys/class/backlight/` to read brightness from.
     #[serde(default = "BacklightConfig::default_device")]
     pub device: Option<String>,
+
+    /// The brightness increment used when scrolling, in percent.
+    #[serde(default = "BacklightConfig::default_step_width")]
+    pub step_width: u64,
+
+    /// Exponent used to map raw brightness to perceived brightness. `1.0`
+    /// keeps the linear behaviour; larger values give finer control near the
+    /// bottom of the range.
+    #[serde(default = "BacklightConfig::default_root_scaling")]
+    pub root_scaling: f64,
+
+    /// Lowest raw brightness the device will be driven to. `0` derives a small
+    /// safety floor from `max_brightness`.
+    #[serde(default = "BacklightConfig::default_minimum_brightness")]
+    pub minimum_brightness: u64,
+
+    /// Format string for the block's text. Supports the `{brightness}` percent
+    /// placeholder as well as the raw `{raw}` and `{max}` sysfs values.
+    #[serde(default = "BacklightConfig::default_format")]
+    pub format: String,
+
+    /// Mouse button that toggles the panel backlight on/off.
+    #[serde(default = "BacklightConfig::default_toggle_button")]
+    pub toggle_button: MouseButton,
 }
 
 impl BacklightConfig {
     fn default_device() -> Option<String> {
         None
     }
+
+    fn default_step_width() -> u64 {
+        5
+    }
+
+    fn default_root_scaling() -> f64 {
+        1.0
+    }
+
+    fn default_minimum_brightness() -> u64 {
+        0
+    }
+
+    fn default_format() -> String {
+        "{brightness}%".to_string()
+    }
+
+    fn default_toggle_button() -> MouseButton {
+        MouseButton::Right
+    }
 }
 
 impl ConfigBlock for Backlight {
     type Config = BacklightConfig;
 
     fn new(block_config: Self::Config, config: Config, tx_update_request: Sender<Task>) -> Result<Self> {
-        let device = try!(match block_config.device {
+        let mut device = try!(match block_config.device {
             Some(path) => BacklitDevice::from_device(path),
             None => BacklitDevice::default(),
         });
+        device.configure(block_config.root_scaling, block_config.minimum_brightness);
 
         let id = Uuid::new_v4().simple().to_string();
-        let brightness_file = device.brightness_file();
 
         let backlight = Backlight {
             output: TextWidget::new(config),
             id: id.clone(),
             device: device,
+            step_width: cmp::min(block_config.step_width, 50),
+            format: try!(FormatTemplate::from_string(&block_config.format)),
+            toggle_button: block_config.toggle_button,
+            update_request: tx_update_request.clone(),
         };
 
-        // Spin up a thread to watch for changes to the brightness file for the
-        // device, and schedule an update if needed.
+        // Spin up a thread to monitor the `backlight` udev subsystem and
+        // schedule an update on every event it delivers (e.g. a `change` event
+        // after a brightness write). The block keeps the device chosen above:
+        // a panel that is added or removed later is not switched to, the
+        // update only re-reads that same device.
         thread::spawn(move || {
-            let mut notify = Inotify::init().expect("Failed to start inotify");
-            notify
-                .add_watch(brightness_file, WatchMask::MODIFY)
-                .expect("Failed to watch brightness file");
+            let context = libudev::Context::new().expect("Failed to create udev context");
+            let mut monitor = libudev::Monitor::new(&context).expect("Failed to create udev monitor");
+            monitor
+                .match_subsystem("backlight")
+                .expect("Failed to filter udev monitor");
+            let mut socket = monitor.listen().expect("Failed to listen on udev monitor");
+
+            // Block on the monitor fd rather than sleep-polling, so the thread
+            // stays parked until the kernel delivers an event.
+            let mut fds = [libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            }];
 
-            let mut buffer = [0; 1024];
             loop {
-                let mut events = notify.read_events_blocking(&mut buffer).expect(
-                    "Error while reading inotify events",
-                );
-
-                if events.any(|event| event.mask.contains(EventMask::MODIFY)) {
-                    tx_update_request.send(Task {
-                        id: id.clone(),
-                        update_time: Instant::now(),
-                    });
+                let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+                if rc < 0 {
+                    thread::sleep(Duration::from_millis(250));
+                    continue;
+                }
+                if fds[0].revents & libc::POLLIN != 0 {
+                    while let Some(_) = socket.receive_event() {
+                        tx_update_request.send(Task {
+                            id: id.clone(),
+                            update_time: Instant::now(),
+                        });
+                    }
                 }
             }
         });
@@ -176,9 +582,20 @@ impl ConfigBlock for Backlight {
 
 impl Block for Backlight {
     fn update(&mut self) -> Result<Option<Duration>> {
-        let brightness = try!(self.device.brightness());
-        let display = ((brightness as f64 / self.device.max_brightness as f64) * 100.0) as u64;
-        self.output.set_text(format!("{}%", display));
+        if !try!(self.device.powered()) {
+            self.output.set_icon("backlight_empty");
+            self.output.set_text("off".to_string());
+            return Ok(None);
+        }
+
+        let display = try!(self.device.brightness());
+        let raw = try!(self.device.raw_brightness());
+        let text = self.format.render(&[
+            ("brightness", display.to_string()),
+            ("raw", raw.to_string()),
+            ("max", self.device.max_brightness.to_string()),
+        ]);
+        self.output.set_text(text);
         match display {
             0...19 => self.output.set_icon("backlight_empty"),
             20...39 => self.output.set_icon("backlight_partial1"),
@@ -193,7 +610,39 @@ impl Block for Backlight {
         vec![&self.output]
     }
 
-    fn click(&mut self, _: &I3BarEvent) -> Result<()> {
+    fn click(&mut self, event: &I3BarEvent) -> Result<()> {
+        // The configurable toggle button blanks/unblanks the panel backlight.
+        if event.button == self.toggle_button {
+            let powered = try!(self.device.powered());
+            try!(self.device.set_power(!powered));
+
+            self.update_request.send(Task {
+                id: self.id.clone(),
+                update_time: Instant::now(),
+            });
+
+            return Ok(());
+        }
+
+        let brightness = try!(self.device.brightness());
+
+        // Scrolling adjusts perceived brightness in `step_width` percent steps;
+        // the raw minimum-brightness floor is enforced in `set_brightness`.
+        let value = match event.button {
+            MouseButton::WheelUp => cmp::min(100, brightness + self.step_width),
+            MouseButton::WheelDown => brightness.saturating_sub(self.step_width),
+            _ => return Ok(()),
+        };
+
+        try!(self.device.set_brightness(value));
+
+        // Reflect the change immediately rather than waiting for the udev
+        // monitor.
+        self.update_request.send(Task {
+            id: self.id.clone(),
+            update_time: Instant::now(),
+        });
+
         Ok(())
     }
 